@@ -0,0 +1,142 @@
+use super::msg::{Error, Op};
+use super::server::{Propose, WithId, Witness};
+use madsim::net;
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+/// Talks to a `kvraft::Server` cluster on behalf of one logical client.
+/// `client_id` must be unique across concurrently-running clerks against the
+/// same cluster, since it's what `Sessions` keys deduplication on.
+pub struct Clerk {
+    servers: Vec<SocketAddr>,
+    client_id: u64,
+    seq: AtomicU64,
+    /// Which server we last heard is the leader; just a hint, not load-bearing
+    /// for correctness - a wrong guess just costs a retry.
+    leader: AtomicUsize,
+}
+
+impl Clerk {
+    pub fn new(servers: Vec<SocketAddr>, client_id: u64) -> Self {
+        Clerk {
+            servers,
+            client_id,
+            seq: AtomicU64::new(0),
+            leader: AtomicUsize::new(0),
+        }
+    }
+
+    pub async fn get(&self, key: String) -> String {
+        self.call(Op::Get { key }).await
+    }
+
+    pub async fn put(&self, key: String, value: String) {
+        self.call(Op::Put { key, value }).await;
+    }
+
+    pub async fn append(&self, key: String, value: String) {
+        self.call(Op::Append { key, value }).await;
+    }
+
+    /// Sends `cmd` to the cluster, retrying against the next server whenever
+    /// the current leader guess turns out to be wrong.
+    ///
+    /// For a mutating `cmd`, this races a `Propose` call to the believed
+    /// leader against a `Witness` broadcast to every replica: `Put`/`Append`
+    /// always produce an empty response regardless of when they execute, so
+    /// once *both* the leader confirms it appended the command to its
+    /// in-memory log *and* a
+    /// super-quorum of witnesses accept it, it's safe to return without
+    /// waiting out the round trip through Raft commit. Either half failing
+    /// falls back to the full `WithId` call - cheap, since `Sessions` dedups
+    /// the resubmission of the same `(client_id, seq)` for free. `Get` always
+    /// goes straight to the leader, which itself may serve it off the
+    /// ReadIndex fast path.
+    async fn call(&self, cmd: Op) -> String {
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst) + 1;
+        let net = net::NetLocalHandle::current();
+        loop {
+            let leader = self.leader.load(Ordering::SeqCst) % self.servers.len();
+
+            if matches!(cmd, Op::Get { .. }) {
+                let args = WithId {
+                    client_id: self.client_id,
+                    seq,
+                    cmd: cmd.clone(),
+                };
+                let reply = net
+                    .call::<_, Result<String, Error>>(self.servers[leader], args)
+                    .await;
+                match reply {
+                    Ok(Ok(response)) => return response,
+                    Ok(Err(Error::NotLeader { hint })) => {
+                        self.leader.store(hint, Ordering::SeqCst)
+                    }
+                    _ => self
+                        .leader
+                        .store((leader + 1) % self.servers.len(), Ordering::SeqCst),
+                }
+                continue;
+            }
+
+            let propose_args = Propose {
+                client_id: self.client_id,
+                seq,
+                cmd: cmd.clone(),
+            };
+            let propose_call =
+                net.call::<_, Result<(), Error>>(self.servers[leader], propose_args);
+            let witness_call = self.broadcast_witness(&net, seq, cmd.clone());
+            let (proposed, accepted) = futures::join!(propose_call, witness_call);
+
+            if matches!(proposed, Ok(Ok(()))) && accepted {
+                return String::new();
+            }
+
+            let args = WithId {
+                client_id: self.client_id,
+                seq,
+                cmd: cmd.clone(),
+            };
+            let reply = net
+                .call::<_, Result<String, Error>>(self.servers[leader], args)
+                .await;
+            match reply {
+                Ok(Ok(response)) => return response,
+                Ok(Err(Error::NotLeader { hint })) => self.leader.store(hint, Ordering::SeqCst),
+                _ => self
+                    .leader
+                    .store((leader + 1) % self.servers.len(), Ordering::SeqCst),
+            }
+        }
+    }
+
+    /// Sends `cmd` as a `Witness` to every replica and waits for all replies,
+    /// returning whether a super-quorum of `⌈3f/2⌉+1` (out of `n = 2f+1`
+    /// replicas) accepted it.
+    async fn broadcast_witness(&self, net: &net::NetLocalHandle, seq: u64, cmd: Op) -> bool {
+        let replies = futures::future::join_all(self.servers.iter().map(|&peer| {
+            let net = net.clone();
+            let args = Witness {
+                client_id: self.client_id,
+                seq,
+                cmd: cmd.clone(),
+            };
+            async move { net.call::<_, bool>(peer, args).await }
+        }))
+        .await;
+        let accepts = replies.iter().filter(|r| matches!(r, Ok(true))).count();
+        accepts >= super_quorum(self.servers.len())
+    }
+}
+
+/// The super-quorum size `⌈3f/2⌉+1` for `n = 2f+1` replicas, as used by the
+/// witness fast path: strictly more than a simple majority, so that two
+/// non-commuting commands can never both reach a super-quorum of witnesses at
+/// once.
+fn super_quorum(n: usize) -> usize {
+    let f = (n - 1) / 2;
+    (3 * f).div_ceil(2) + 1
+}
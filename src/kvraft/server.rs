@@ -9,7 +9,7 @@ use madsim::{
 };
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::HashMap,
     fmt::{self, Debug},
     net::SocketAddr,
     sync::{Arc, Mutex},
@@ -17,12 +17,34 @@ use std::{
 
 pub trait State: Serialize + DeserializeOwned + Debug + Send + 'static {
     type Command: Request + Clone + Debug;
-    fn apply(&mut self, id: u64, cmd: Self::Command) -> <Self::Command as Request>::Response;
+    fn apply(
+        &mut self,
+        client_id: u64,
+        seq: u64,
+        cmd: Self::Command,
+    ) -> <Self::Command as Request>::Response;
+
+    /// Whether `cmd` is read-only and can be served through the ReadIndex fast
+    /// path instead of going through the Raft log. Defaults to `false`; override
+    /// for commands (like `Op::Get`) that never mutate state.
+    fn is_read_only(_cmd: &Self::Command) -> bool {
+        false
+    }
+
+    /// Whether `a` and `b` commute, i.e. applying them in either order leaves
+    /// `self` in the same state. Used by the CURP-style witness fast path to
+    /// decide whether a speculative command can be accepted without waiting for
+    /// it to commit through Raft. Defaults to `false` (conservatively never
+    /// commute); override to allow speculation for commands that actually do.
+    fn commutes(&self, _a: &Self::Command, _b: &Self::Command) -> bool {
+        false
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub(super) struct WithId<R> {
-    pub id: u64,
+    pub client_id: u64,
+    pub seq: u64,
     pub cmd: R,
 }
 
@@ -32,12 +54,84 @@ impl<R: Request> Request for WithId<R> {
     const ID: u64 = 1;
 }
 
+/// CURP-style speculative command sent by the client to every replica acting as
+/// a witness, concurrently with the `Propose` sent to the leader. A witness
+/// accepts it (returning `true`) iff it commutes with everything already in its
+/// `witness` set; the client treats the command as committed once a super-quorum
+/// of witnesses accept and the leader acknowledges the `Propose`, without
+/// waiting for the Raft commit.
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct Witness<R> {
+    pub client_id: u64,
+    pub seq: u64,
+    pub cmd: R,
+}
+
+impl<R: Request> Request for Witness<R> {
+    type Response = bool;
+    const ID: u64 = 2;
+}
+
+/// Sent by `Clerk` to the believed leader alongside its `Witness` broadcast:
+/// a cheap "appended to the log" acknowledgment, so the witness fast path can
+/// honor its invariant that it never reports success on a super-quorum of
+/// witness accepts alone - those say nothing about whether the leader ever
+/// saw the command. Resolves as soon as the entry is appended to the leader's
+/// in-memory log, without waiting out the full commit; the Raft log is not
+/// persisted to disk.
+#[derive(Debug, Serialize, Deserialize)]
+pub(super) struct Propose<R> {
+    pub client_id: u64,
+    pub seq: u64,
+    pub cmd: R,
+}
+
+impl<R: Request> Request for Propose<R> {
+    type Response = Result<(), Error>;
+    const ID: u64 = 3;
+}
+
 pub struct Server<S: State> {
     rf: raft::RaftHandle,
     me: usize,
-    rpcs: Arc<Rpcs<<S::Command as Request>::Response>>,
+    rpcs: Arc<Rpcs<Result<<S::Command as Request>::Response, Error>>>,
     state: Arc<Mutex<S>>,
+    /// Highest log index applied to `state` so far, used by the ReadIndex read
+    /// path to know when it is safe to read without appending to the log.
+    applied_index: Arc<Mutex<u64>>,
+    /// Commands accepted via the witness fast path that have not yet been seen
+    /// committed through Raft. Entries are removed once the apply loop observes
+    /// the matching `(client_id, seq)`, and are re-proposed if this server becomes
+    /// leader while they are still outstanding.
+    witness: Arc<Mutex<Vec<WitnessEntry<S::Command>>>>,
+    /// Commands waiting for the next flush into a batched Raft log entry.
+    #[allow(clippy::type_complexity)]
+    pending:
+        Arc<Mutex<Vec<PendingCmd<S::Command, Result<<S::Command as Request>::Response, Error>>>>>,
     _bg_task: task::JoinHandle<()>,
+    _witness_task: task::JoinHandle<()>,
+    _flush_task: task::JoinHandle<()>,
+}
+
+struct WitnessEntry<C> {
+    client_id: u64,
+    seq: u64,
+    cmd: C,
+    /// Set once this server (as leader) has re-proposed the entry, so the
+    /// reconciler doesn't keep re-appending it every tick.
+    reproposed: bool,
+}
+
+struct PendingCmd<C, T> {
+    client_id: u64,
+    seq: u64,
+    cmd: C,
+    sender: oneshot::Sender<T>,
+    /// Fired as soon as `rf.start` appends this command to the leader's
+    /// in-memory log (not persisted), independent
+    /// of `sender` (which waits for the full commit) - `propose` awaits this
+    /// instead, to answer "did the leader see it" cheaply.
+    registered: Option<oneshot::Sender<Result<(), Error>>>,
 }
 
 impl<S: State> fmt::Debug for Server<S> {
@@ -46,6 +140,12 @@ impl<S: State> fmt::Debug for Server<S> {
     }
 }
 
+/// Default window a batch is left open to accumulate commands before being
+/// proposed as a single Raft log entry.
+const DEFAULT_BATCH_WINDOW: Duration = Duration::from_millis(1);
+/// Default cap on the number of commands coalesced into one log entry.
+const DEFAULT_MAX_BATCH_SIZE: usize = 100;
+
 impl<S: State + Default> Server<S> {
     pub async fn new(
         servers: Vec<SocketAddr>,
@@ -55,16 +155,27 @@ impl<S: State + Default> Server<S> {
     where
         <S::Command as Request>::Response: Debug,
     {
-        Self::new_with_state(servers, me, max_raft_state, S::default()).await
+        Self::new_with_state(
+            servers,
+            me,
+            max_raft_state,
+            S::default(),
+            DEFAULT_BATCH_WINDOW,
+            DEFAULT_MAX_BATCH_SIZE,
+        )
+        .await
     }
 }
 
 impl<S: State> Server<S> {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new_with_state(
         servers: Vec<SocketAddr>,
         me: usize,
         max_raft_state: Option<usize>,
         state0: S,
+        batch_window: Duration,
+        max_batch_size: usize,
     ) -> Arc<Self>
     where
         <S::Command as Request>::Response: Debug,
@@ -74,10 +185,14 @@ impl<S: State> Server<S> {
 
         let rpcs = Arc::new(Rpcs::default());
         let state = Arc::new(Mutex::new(state0));
+        let applied_index = Arc::new(Mutex::new(0));
+        let witness = Arc::new(Mutex::new(Vec::<WitnessEntry<S::Command>>::new()));
 
         let rpcs0 = rpcs.clone();
         let rf0 = rf.clone();
         let state0 = state.clone();
+        let applied_index0 = applied_index.clone();
+        let witness0 = witness.clone();
         let _bg_task = task::spawn_local(async move {
             while let Some(msg) = apply_ch.next().await {
                 let state_index;
@@ -88,13 +203,41 @@ impl<S: State> Server<S> {
                         state_index = index;
                     }
                     raft::ApplyMsg::Command { index, data } => {
-                        let (id, cmd): (u64, S::Command) = bincode::deserialize(&data).unwrap();
-                        let ret = state0.lock().unwrap().apply(id, cmd.clone());
-                        debug!("apply [{:04x}] {:?} => {:?}", id as u16, cmd, ret);
+                        let batch: Vec<(u64, u64, S::Command)> =
+                            bincode::deserialize(&data).unwrap();
+                        let mut results = Vec::with_capacity(batch.len());
+                        {
+                            let mut state = state0.lock().unwrap();
+                            for (client_id, seq, cmd) in &batch {
+                                let ret = state.apply(*client_id, *seq, cmd.clone());
+                                debug!(
+                                    "apply [{:04x}/{}] {:?} => {:?}",
+                                    *client_id as u16, seq, cmd, ret
+                                );
+                                results.push(((*client_id, *seq), Ok(ret)));
+                            }
+                        }
                         state_index = index;
-                        rpcs0.complete(index, id, ret);
+                        rpcs0.complete(index, results);
+                        let mut witness = witness0.lock().unwrap();
+                        for (client_id, seq, _) in &batch {
+                            witness.retain(|e| (e.client_id, e.seq) != (*client_id, *seq));
+                        }
+                    }
+                    raft::ApplyMsg::Config { index, addrs } => {
+                        // Delivered as soon as the entry appears in the log,
+                        // not once committed, so old and new majorities
+                        // always overlap - meaning a Config at index N can
+                        // arrive before a still-uncommitted Command at some
+                        // index < N has. Nothing here touches `state`, so it
+                        // must not advance `applied_index` - doing so would
+                        // let a ReadIndex-gated read past a Command that
+                        // hasn't actually been applied yet.
+                        debug!("apply config change at index {}: {:?}", index, addrs);
+                        continue;
                     }
                 }
+                *applied_index0.lock().unwrap() = state_index;
                 // snapshot if needed
                 if let Some(size) = max_raft_state {
                     if fs::metadata("state").await.map(|m| m.len()).unwrap_or(0) >= size as u64 {
@@ -105,12 +248,115 @@ impl<S: State> Server<S> {
             }
         });
 
+        // Periodically re-propose witnessed-but-unsynced commands when this
+        // server becomes leader, so a dead old leader can never strand a
+        // speculatively-committed command.
+        let rf1 = rf.clone();
+        let witness1 = witness.clone();
+        let _witness_task = task::spawn_local(async move {
+            loop {
+                madsim::time::sleep(Duration::from_millis(20)).await;
+                if !rf1.is_leader() {
+                    continue;
+                }
+                let to_repropose: Vec<_> = witness1
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|e| !e.reproposed)
+                    .map(|e| (e.client_id, e.seq, e.cmd.clone()))
+                    .collect();
+                for (client_id, seq, cmd) in to_repropose {
+                    let proposed = rf1
+                        .start(&bincode::serialize(&vec![(client_id, seq, cmd)]).unwrap())
+                        .await
+                        .is_ok();
+                    // Only mark it done once it's actually in the log; leave it
+                    // for the next tick to retry if this node lost leadership (or
+                    // some other transient error) mid-repropose, so a failed
+                    // attempt can never strand the entry forever.
+                    if proposed {
+                        if let Some(e) = witness1
+                            .lock()
+                            .unwrap()
+                            .iter_mut()
+                            .find(|e| (e.client_id, e.seq) == (client_id, seq))
+                        {
+                            e.reproposed = true;
+                        }
+                    }
+                }
+            }
+        });
+
+        // Every `batch_window`, coalesces up to `max_batch_size` accumulated
+        // commands into a single Raft log entry, so N concurrent requests cost
+        // one AppendEntries round instead of N. Any commands beyond
+        // `max_batch_size` wait for the next tick; reaching the cap does not
+        // trigger an early flush.
+        let pending = Arc::new(Mutex::new(Vec::<
+            PendingCmd<S::Command, Result<<S::Command as Request>::Response, Error>>,
+        >::new()));
+        let rpcs1 = rpcs.clone();
+        let rf2 = rf.clone();
+        let pending0 = pending.clone();
+        let _flush_task = task::spawn_local(async move {
+            loop {
+                madsim::time::sleep(batch_window).await;
+                let batch: Vec<_> = {
+                    let mut pending = pending0.lock().unwrap();
+                    let n = pending.len().min(max_batch_size);
+                    pending.drain(..n).collect()
+                };
+                if batch.is_empty() {
+                    continue;
+                }
+                let mut entries = Vec::with_capacity(batch.len());
+                let mut waiters = Vec::with_capacity(batch.len());
+                for PendingCmd {
+                    client_id,
+                    seq,
+                    cmd,
+                    sender,
+                    registered,
+                } in batch
+                {
+                    entries.push((client_id, seq, cmd));
+                    waiters.push(((client_id, seq), sender, registered));
+                }
+                match rf2.start(&bincode::serialize(&entries).unwrap()).await {
+                    Ok(s) => {
+                        for (id, sender, registered) in waiters {
+                            if let Some(registered) = registered {
+                                let _ = registered.send(Ok(()));
+                            }
+                            rpcs1.register(s.index, id, sender);
+                        }
+                    }
+                    Err(raft::Error::NotLeader(hint)) => {
+                        for (_, sender, registered) in waiters {
+                            if let Some(registered) = registered {
+                                let _ = registered.send(Err(Error::NotLeader { hint }));
+                            }
+                            let _ = sender.send(Err(Error::NotLeader { hint }));
+                        }
+                    }
+                    _ => unreachable!(),
+                }
+            }
+        });
+
         let this = Arc::new(Server {
             rf,
             me,
             rpcs,
             state,
+            applied_index,
+            witness,
+            pending,
             _bg_task,
+            _witness_task,
+            _flush_task,
         });
         this.start_rpc_server();
         this
@@ -122,8 +368,53 @@ impl<S: State> Server<S> {
         let this = self.clone();
         net.add_rpc_handler(move |req: WithId<S::Command>| {
             let this = this.clone();
-            async move { this.apply(req.id, req.cmd).await }
+            async move {
+                if S::is_read_only(&req.cmd) {
+                    this.read(req.client_id, req.seq, req.cmd).await
+                } else {
+                    this.apply(req.client_id, req.seq, req.cmd).await
+                }
+            }
+        });
+
+        let this = self.clone();
+        net.add_rpc_handler(move |req: Witness<S::Command>| {
+            let this = this.clone();
+            async move { this.witness_accept(req.client_id, req.seq, req.cmd) }
+        });
+
+        let this = self.clone();
+        net.add_rpc_handler(move |req: Propose<S::Command>| {
+            let this = this.clone();
+            async move { this.propose(req.client_id, req.seq, req.cmd).await }
+        });
+    }
+
+    /// Accepts `cmd` into the witness set iff it commutes with every command
+    /// already recorded there, i.e. it is safe to speculate that all of them
+    /// commit in some order without affecting each other's result.
+    fn witness_accept(&self, client_id: u64, seq: u64, cmd: S::Command) -> bool {
+        let state = self.state.lock().unwrap();
+        let mut witness = self.witness.lock().unwrap();
+        if witness
+            .iter()
+            .any(|e| (e.client_id, e.seq) == (client_id, seq))
+        {
+            // A retry of the same (client_id, seq) that's already witnessed -
+            // a command never commutes with an identical copy of itself, so
+            // the commutativity check below would spuriously reject it.
+            return true;
+        }
+        if witness.iter().any(|e| !state.commutes(&e.cmd, &cmd)) {
+            return false;
+        }
+        witness.push(WitnessEntry {
+            client_id,
+            seq,
+            cmd,
+            reproposed: false,
         });
+        true
     }
 
     /// The current term of this peer.
@@ -140,33 +431,119 @@ impl<S: State> Server<S> {
         &self.state
     }
 
+    /// The cluster's current membership, as applied from the Raft log so far.
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        self.rf.peers()
+    }
+
+    /// Adds `addr` to the cluster. Replicates a single `Config` entry
+    /// through the Raft log; old and new majorities always overlap because
+    /// membership changes one server at a time.
+    pub async fn add_server(&self, addr: SocketAddr) -> Result<(), Error> {
+        self.rf.add_server(addr).await.map_err(|_| Error::Failed)
+    }
+
+    /// Removes `addr` from the cluster, the same way as `add_server`.
+    pub async fn remove_server(&self, addr: SocketAddr) -> Result<(), Error> {
+        self.rf.remove_server(addr).await.map_err(|_| Error::Failed)
+    }
+
     async fn apply(
         &self,
-        id: u64,
+        client_id: u64,
+        seq: u64,
         cmd: S::Command,
     ) -> Result<<S::Command as Request>::Response, Error> {
-        let index = match self
-            .rf
-            .start(&bincode::serialize(&(id, cmd)).unwrap())
-            .await
-        {
-            Ok(s) => s.index,
-            Err(raft::Error::NotLeader(hint)) => return Err(Error::NotLeader { hint }),
-            _ => unreachable!(),
-        };
-        let recver = self.rpcs.register(index, id);
+        let (sender, recver) = oneshot::channel();
+        self.pending.lock().unwrap().push(PendingCmd {
+            client_id,
+            seq,
+            cmd,
+            sender,
+            registered: None,
+        });
         let output = timeout(Duration::from_millis(500), recver)
             .await
             .map_err(|_| Error::Timeout)?
             .map_err(|_| Error::Failed)?;
-        Ok(output)
+        output
+    }
+
+    /// Appends `cmd` to the Raft log (if this is the leader) and returns as
+    /// soon as it's recorded in the leader's in-memory log, without waiting
+    /// for it to commit; the log is not persisted to disk.
+    /// Backs the `Propose` RPC that `Clerk` races against its witness
+    /// broadcast; the eventual committed response still goes out through
+    /// `apply`/`read`'s normal path once some caller issues the same
+    /// `(client_id, seq)` - `Sessions` makes that resubmission free.
+    async fn propose(&self, client_id: u64, seq: u64, cmd: S::Command) -> Result<(), Error> {
+        let (sender, _recver) = oneshot::channel();
+        let (registered, wait_registered) = oneshot::channel();
+        self.pending.lock().unwrap().push(PendingCmd {
+            client_id,
+            seq,
+            cmd,
+            sender,
+            registered: Some(registered),
+        });
+        timeout(Duration::from_millis(500), wait_registered)
+            .await
+            .map_err(|_| Error::Timeout)?
+            .map_err(|_| Error::Failed)?
+    }
+
+    /// Linearizable read-only fast path (ReadIndex): serve `cmd` directly against
+    /// `state` without appending anything to the Raft log. The leader records its
+    /// current commit index as the read index, confirms its leadership with a
+    /// round of heartbeats to a majority, then waits for `state` to catch up to
+    /// that index before reading - this rules out a stale leader serving reads
+    /// from data later overwritten on a newer leader. Bounded by the same
+    /// 500ms timeout as `apply`, so a stuck confirm-leadership round or a
+    /// lagging `state` can't hang the caller forever; a freshly-elected
+    /// leader that hasn't committed its no-op yet is retried rather than
+    /// failed outright, since it should clear within the same window.
+    async fn read(
+        &self,
+        client_id: u64,
+        seq: u64,
+        cmd: S::Command,
+    ) -> Result<<S::Command as Request>::Response, Error> {
+        let attempt = async {
+            loop {
+                match self.rf.read_index().await {
+                    Ok(index) => {
+                        self.wait_applied(index).await;
+                        return Ok(self.state.lock().unwrap().apply(client_id, seq, cmd.clone()));
+                    }
+                    Err(raft::Error::NotLeader(hint)) => return Err(Error::NotLeader { hint }),
+                    Err(raft::Error::NotReady) => {
+                        madsim::time::sleep(Duration::from_millis(10)).await;
+                    }
+                    _ => return Err(Error::Failed),
+                }
+            }
+        };
+        timeout(Duration::from_millis(500), attempt)
+            .await
+            .map_err(|_| Error::Timeout)?
+    }
+
+    /// Blocks until `applied_index >= index`, i.e. until `state` has replayed
+    /// every log entry up to `index`.
+    async fn wait_applied(&self, index: u64) {
+        while *self.applied_index.lock().unwrap() < index {
+            madsim::time::sleep(Duration::from_millis(1)).await;
+        }
     }
 }
 
-/// Pending RPCs register center.
+/// Pending RPCs register center. A single log `index` now carries a batch of
+/// commands, so it maps to several `(client_id, seq)` waiters instead of one.
+/// `register` always runs before the matching `complete` (both are gated on
+/// `rf.start` returning `index`), so no waiter can be missed.
 struct Rpcs<T> {
-    // { index -> (id, sender) }
-    rpcs: Mutex<HashMap<u64, (u64, oneshot::Sender<T>)>>,
+    // { index -> [((client_id, seq), sender)] }
+    rpcs: Mutex<HashMap<u64, Vec<((u64, u64), oneshot::Sender<T>)>>>,
 }
 
 impl<T> Default for Rpcs<T> {
@@ -178,57 +555,114 @@ impl<T> Default for Rpcs<T> {
 }
 
 impl<T> Rpcs<T> {
-    fn register(&self, index: u64, id: u64) -> oneshot::Receiver<T> {
-        let (sender, recver) = oneshot::channel();
-        self.rpcs.lock().unwrap().insert(index, (id, sender));
-        recver
+    fn register(&self, index: u64, id: (u64, u64), sender: oneshot::Sender<T>) {
+        self.rpcs
+            .lock()
+            .unwrap()
+            .entry(index)
+            .or_default()
+            .push((id, sender));
     }
 
-    fn complete(&self, index: u64, id: u64, value: T) {
-        let mut rpcs = self.rpcs.lock().unwrap();
-        if let Some((id0, sender)) = rpcs.remove(&index) {
-            if id == id0 {
-                // message match, success
+    /// Delivers each waiter registered for `index` its matching result, keyed by
+    /// `(client_id, seq)`.
+    fn complete(&self, index: u64, mut results: Vec<((u64, u64), T)>) {
+        let waiters = match self.rpcs.lock().unwrap().remove(&index) {
+            Some(waiters) => waiters,
+            None => return,
+        };
+        for (id, sender) in waiters {
+            if let Some(pos) = results.iter().position(|(rid, _)| *rid == id) {
+                let (_, value) = results.remove(pos);
                 let _ = sender.send(value);
             }
-            // otherwise drop the sender
+            // otherwise no matching result in this batch; drop the sender
+        }
+    }
+}
+
+/// Per-client session table, keyed by `client_id`, that makes a [`State`]'s `apply`
+/// idempotent under Raft's at-least-once command delivery: a retried command with
+/// `seq <= last_seq` replays the cached response instead of being applied again.
+/// Embed this in a `State` implementor's own struct to get it snapshotted for free.
+/// Unlike the bounded ring buffer this replaces, entries are kept forever - no
+/// eviction, so a growing client population grows this table without bound.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Sessions<T> {
+    // { client_id -> (last_seq, last_response) }
+    sessions: HashMap<u64, (u64, T)>,
+}
+
+impl<T> Default for Sessions<T> {
+    fn default() -> Self {
+        Self {
+            sessions: Default::default(),
         }
     }
 }
 
+impl<T: Clone> Sessions<T> {
+    /// Returns the response cached for `client_id` if `seq` is no newer than the
+    /// last `seq` recorded for it, so the caller can skip mutating state and
+    /// replay it verbatim. Only each client's latest response is kept, so for
+    /// an older `seq` this is the latest recorded response, not that `seq`'s own.
+    pub fn cached(&self, client_id: u64, seq: u64) -> Option<T> {
+        let (last_seq, last_response) = self.sessions.get(&client_id)?;
+        (seq <= *last_seq).then(|| last_response.clone())
+    }
+
+    /// Records that `seq` was just applied for `client_id`, caching its response.
+    pub fn record(&mut self, client_id: u64, seq: u64, response: T) {
+        self.sessions.insert(client_id, (seq, response));
+    }
+}
+
 pub type KvServer = Server<Kv>;
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Kv {
     kv: HashMap<String, String>,
-    ids: VecDeque<u32>,
+    sessions: Sessions<String>,
 }
 
 impl State for Kv {
     type Command = Op;
 
-    fn apply(&mut self, id: u64, cmd: Op) -> String {
-        match cmd {
-            Op::Put { key, value } if self.test_dup_id(id) => {
-                self.kv.insert(key, value);
-            }
-            Op::Append { key, value } if self.test_dup_id(id) => {
-                self.kv.entry(key).or_default().push_str(&value);
+    fn is_read_only(cmd: &Op) -> bool {
+        matches!(cmd, Op::Get { .. })
+    }
+
+    fn commutes(&self, a: &Op, b: &Op) -> bool {
+        fn key_and_write(op: &Op) -> (&str, bool) {
+            match op {
+                Op::Get { key } => (key, false),
+                Op::Put { key, .. } | Op::Append { key, .. } => (key, true),
             }
-            Op::Get { key } => return self.kv.get(&key).cloned().unwrap_or_default(),
-            _ => {}
         }
-        "".into()
+        let (key_a, write_a) = key_and_write(a);
+        let (key_b, write_b) = key_and_write(b);
+        key_a != key_b || (!write_a && !write_b)
     }
-}
 
-impl Kv {
-    fn test_dup_id(&mut self, id: u64) -> bool {
-        let unique = !self.ids.contains(&(id as u32));
-        if self.ids.len() >= 100 {
-            self.ids.pop_front();
+    fn apply(&mut self, client_id: u64, seq: u64, cmd: Op) -> String {
+        if let Op::Get { key } = &cmd {
+            return self.kv.get(key).cloned().unwrap_or_default();
+        }
+        if let Some(response) = self.sessions.cached(client_id, seq) {
+            return response;
         }
-        self.ids.push_back(id as u32);
-        unique
+        let response = match cmd {
+            Op::Put { key, value } => {
+                self.kv.insert(key, value);
+                String::new()
+            }
+            Op::Append { key, value } => {
+                self.kv.entry(key).or_default().push_str(&value);
+                String::new()
+            }
+            Op::Get { .. } => unreachable!(),
+        };
+        self.sessions.record(client_id, seq, response.clone());
+        response
     }
 }
@@ -0,0 +1,892 @@
+//! A small Raft core used by `kvraft::Server`. Implements leader election, log
+//! replication, the ReadIndex read-only fast path, single-server membership
+//! changes, and InstallSnapshot-based catch-up for a follower (or a newly
+//! added server) whose log has fallen behind what the leader still has
+//! in-memory.
+
+mod msg;
+
+pub(crate) use msg::{
+    AppendEntriesArgs, AppendEntriesReply, EntryPayload, InstallSnapshotArgs, InstallSnapshotReply,
+    LogEntry, RequestVoteArgs, RequestVoteReply,
+};
+pub use msg::{ApplyMsg, Error, Start};
+
+use futures::channel::mpsc;
+use madsim::{
+    net::{self, rpc::Request},
+    task,
+    time::{self, Duration},
+};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+const ELECTION_TIMEOUT_MIN: Duration = Duration::from_millis(150);
+const ELECTION_TIMEOUT_MAX: Duration = Duration::from_millis(300);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+struct Inner {
+    me: SocketAddr,
+    /// Full current membership, kept up to date as soon as a `Config` entry is
+    /// appended to this node's log (leader or follower) - this is what RPC
+    /// fan-out (heartbeats, vote requests) targets.
+    peers: Vec<SocketAddr>,
+    /// Membership used to decide whether an index has a majority, i.e. to
+    /// advance `commit_index`. Only updated once a `Config` entry *commits*, so
+    /// a membership change's own commit still needs a majority of the old
+    /// configuration, guaranteeing old and new majorities overlap.
+    commit_peers: Vec<SocketAddr>,
+    /// Servers added via `add_server` that haven't yet replicated up to the
+    /// index given here, and so aren't trusted toward a quorum count yet -
+    /// otherwise a brand new server with an empty log would count toward
+    /// committing entries it hasn't actually stored.
+    catching_up: HashMap<SocketAddr, u64>,
+    /// Whether a `Config` entry has been proposed but not yet committed -
+    /// guards against overlapping membership changes, since a second one
+    /// proposed before the first commits could let old and new majorities
+    /// fail to overlap.
+    pending_config: bool,
+
+    current_term: u64,
+    voted_for: Option<SocketAddr>,
+    /// `log[i]` holds raft log index `snapshot_index + i`; `log[0]` is always
+    /// a sentinel standing in for `snapshot_index` itself (real on first boot,
+    /// where `snapshot_index` is 0 and the sentinel's payload is never read).
+    log: Vec<LogEntry>,
+    commit_index: u64,
+    last_applied: u64,
+
+    /// Index/term of the most recent snapshot (0/0 if none has been taken),
+    /// and the state it captured - retained so a follower that needs entries
+    /// this leader has already compacted away can be sent this instead of
+    /// being stuck retrying AppendEntries forever.
+    snapshot_index: u64,
+    snapshot_term: u64,
+    snapshot_data: Vec<u8>,
+
+    role: Role,
+    leader: Option<SocketAddr>,
+    election_deadline: time::Instant,
+
+    next_index: HashMap<SocketAddr, u64>,
+    match_index: HashMap<SocketAddr, u64>,
+
+    apply_tx: mpsc::UnboundedSender<ApplyMsg>,
+}
+
+/// A cheaply-cloneable handle to a node's Raft core.
+#[derive(Clone)]
+pub struct RaftHandle {
+    inner: Arc<Mutex<Inner>>,
+    /// Keeps the background election/heartbeat/apply tasks alive for as long as
+    /// any clone of this handle is - dropping a `JoinHandle` cancels its task.
+    _tasks: Arc<Mutex<Vec<task::JoinHandle<()>>>>,
+}
+
+impl RaftHandle {
+    pub async fn new(
+        servers: Vec<SocketAddr>,
+        me: usize,
+    ) -> (Self, mpsc::UnboundedReceiver<ApplyMsg>) {
+        let (apply_tx, apply_rx) = mpsc::unbounded();
+        let my_addr = servers[me];
+        let inner = Inner {
+            me: my_addr,
+            peers: servers.clone(),
+            commit_peers: servers,
+            catching_up: HashMap::new(),
+            pending_config: false,
+            current_term: 0,
+            voted_for: None,
+            log: vec![LogEntry {
+                term: 0,
+                payload: EntryPayload::Command(Vec::new()),
+            }],
+            commit_index: 0,
+            last_applied: 0,
+            snapshot_index: 0,
+            snapshot_term: 0,
+            snapshot_data: Vec::new(),
+            role: Role::Follower,
+            leader: None,
+            election_deadline: time::Instant::now() + election_timeout(my_addr, 0),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            apply_tx,
+        };
+        let this = RaftHandle {
+            inner: Arc::new(Mutex::new(inner)),
+            _tasks: Arc::new(Mutex::new(Vec::new())),
+        };
+
+        this.start_rpc_server();
+        this.spawn_election_timer();
+        this.spawn_heartbeat_loop();
+        this.spawn_apply_loop();
+
+        (this, apply_rx)
+    }
+
+    fn start_rpc_server(&self) {
+        let net = net::NetLocalHandle::current();
+
+        let this = self.clone();
+        net.add_rpc_handler(move |args: RequestVoteArgs| {
+            let this = this.clone();
+            async move { this.handle_request_vote(args) }
+        });
+
+        let this = self.clone();
+        net.add_rpc_handler(move |args: AppendEntriesArgs| {
+            let this = this.clone();
+            async move { this.handle_append_entries(args) }
+        });
+
+        let this = self.clone();
+        net.add_rpc_handler(move |args: InstallSnapshotArgs| {
+            let this = this.clone();
+            async move { this.handle_install_snapshot(args) }
+        });
+    }
+
+    /// The current term of this peer.
+    pub fn term(&self) -> u64 {
+        self.inner.lock().unwrap().current_term
+    }
+
+    /// Whether this peer believes it is the leader.
+    pub fn is_leader(&self) -> bool {
+        self.inner.lock().unwrap().role == Role::Leader
+    }
+
+    /// Whether this peer is still part of the cluster according to its own
+    /// (possibly stale) view of the membership.
+    fn is_member(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.peers.contains(&inner.me)
+    }
+
+    /// Appends `data` as a new log entry if this peer is the leader. Returns
+    /// immediately after appending locally; the caller learns the command
+    /// committed by observing it come back out on the `ApplyMsg` channel.
+    pub async fn start(&self, data: &[u8]) -> Result<Start, Error> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.role != Role::Leader {
+            return Err(Error::NotLeader(inner.leader_hint()));
+        }
+        let term = inner.current_term;
+        inner.log.push(LogEntry {
+            term,
+            payload: EntryPayload::Command(data.to_vec()),
+        });
+        Ok(Start {
+            index: inner.last_index(),
+            term,
+        })
+    }
+
+    /// Discards log entries up to and including `index`, now that `data`
+    /// captures an equivalent snapshot of the state machine.
+    pub async fn snapshot(&self, index: u64, data: &[u8]) -> Result<(), Error> {
+        let mut inner = self.inner.lock().unwrap();
+        if index <= inner.snapshot_index || index > inner.last_index() {
+            return Ok(());
+        }
+        let keep_from = inner.arr(index);
+        let term = inner.log[keep_from].term;
+        inner.log.drain(0..keep_from);
+        // log[0] now stands in for `index`/`term`; its payload is already
+        // captured by `data` and is never read again.
+        inner.log[0] = LogEntry {
+            term,
+            payload: EntryPayload::Command(Vec::new()),
+        };
+        inner.snapshot_index = index;
+        inner.snapshot_term = term;
+        inner.snapshot_data = data.to_vec();
+        Ok(())
+    }
+
+    /// ReadIndex: returns a log index such that, once this peer has applied up
+    /// to it, a read started now is linearizable. Captures the current commit
+    /// index, then confirms leadership by exchanging one heartbeat round with a
+    /// majority - this rules out serving a stale read from a peer that (unknown
+    /// to it) has already been superseded by a newer leader.
+    pub async fn read_index(&self) -> Result<u64, Error> {
+        let (read_index, peers, term) = {
+            let inner = self.inner.lock().unwrap();
+            if inner.role != Role::Leader {
+                return Err(Error::NotLeader(inner.leader_hint()));
+            }
+            // `commit_index` might still only reflect entries committed by a
+            // past leader, which a future leader could still overwrite - not
+            // actually safe to treat as a read watermark until this leader
+            // has committed its own no-op in its own current term.
+            if inner.log[inner.arr(inner.commit_index)].term != inner.current_term {
+                return Err(Error::NotReady);
+            }
+            (inner.commit_index, inner.peers.clone(), inner.current_term)
+        };
+        if self.confirm_leadership(&peers, term).await {
+            Ok(read_index)
+        } else {
+            Err(Error::NotLeader(self.inner.lock().unwrap().leader_hint()))
+        }
+    }
+
+    /// Sends one heartbeat round to every peer and waits for acks from a
+    /// majority (including self), all at `term`. Returns `false` if a reply
+    /// reveals a newer term, meaning this peer is no longer actually leading.
+    async fn confirm_leadership(&self, peers: &[SocketAddr], term: u64) -> bool {
+        let net = net::NetLocalHandle::current();
+        let (me, prev_log_index, prev_log_term, leader_commit) = {
+            let inner = self.inner.lock().unwrap();
+            let prev_log_index = inner.last_index();
+            let prev_log_term = inner.log[inner.arr(prev_log_index)].term;
+            (inner.me, prev_log_index, prev_log_term, inner.commit_index)
+        };
+        let mut acks = 1; // self
+        let replies = futures::future::join_all(peers.iter().filter(|&&p| p != me).map(|&peer| {
+            let net = net.clone();
+            let args = AppendEntriesArgs {
+                term,
+                leader: me,
+                prev_log_index,
+                prev_log_term,
+                entries: Vec::new(),
+                leader_commit,
+            };
+            async move { net.call::<_, Result<AppendEntriesReply, Error>>(peer, args).await }
+        }))
+        .await;
+        for reply in replies.into_iter().flatten() {
+            let Ok(reply) = reply else { continue };
+            if reply.term > term {
+                self.step_down(reply.term);
+                return false;
+            }
+            if reply.success {
+                acks += 1;
+            }
+        }
+        acks * 2 > peers.len()
+    }
+
+    /// Adds `addr` to the cluster via a single-server membership change.
+    /// Proactively sends it the most recent snapshot first, so it isn't left
+    /// counting toward quorum (or stuck retrying AppendEntries) before it has
+    /// anything to replicate onto.
+    pub async fn add_server(&self, addr: SocketAddr) -> Result<(), Error> {
+        self.catch_up(addr).await?;
+        let new_config = {
+            let inner = self.inner.lock().unwrap();
+            let mut config = inner.peers.clone();
+            if !config.contains(&addr) {
+                config.push(addr);
+            }
+            config
+        };
+        self.propose_config(new_config, Some(addr)).await
+    }
+
+    /// Removes `addr` from the cluster via a single-server membership change.
+    pub async fn remove_server(&self, addr: SocketAddr) -> Result<(), Error> {
+        let new_config = {
+            let inner = self.inner.lock().unwrap();
+            inner.peers.iter().copied().filter(|&p| p != addr).collect()
+        };
+        self.propose_config(new_config, None).await
+    }
+
+    /// Sends `addr` the most recent snapshot, if one has been taken, so a
+    /// brand new server isn't left with an empty log the leader can no
+    /// longer replicate to via ordinary AppendEntries. A no-op if nothing has
+    /// been snapshotted yet - normal replication from index 1 is enough then.
+    async fn catch_up(&self, addr: SocketAddr) -> Result<(), Error> {
+        let (term, me, last_included_index, last_included_term, data) = {
+            let inner = self.inner.lock().unwrap();
+            if inner.role != Role::Leader {
+                return Err(Error::NotLeader(inner.leader_hint()));
+            }
+            if inner.snapshot_index == 0 {
+                return Ok(());
+            }
+            (
+                inner.current_term,
+                inner.me,
+                inner.snapshot_index,
+                inner.snapshot_term,
+                inner.snapshot_data.clone(),
+            )
+        };
+        let net = net::NetLocalHandle::current();
+        let args = InstallSnapshotArgs {
+            term,
+            leader: me,
+            last_included_index,
+            last_included_term,
+            data,
+        };
+        let _ = net
+            .call::<_, Result<InstallSnapshotReply, Error>>(addr, args)
+            .await;
+        Ok(())
+    }
+
+    async fn propose_config(
+        &self,
+        new_config: Vec<SocketAddr>,
+        catch_up_target: Option<SocketAddr>,
+    ) -> Result<(), Error> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.role != Role::Leader {
+            return Err(Error::NotLeader(inner.leader_hint()));
+        }
+        if inner.pending_config {
+            return Err(Error::ConfigChangePending);
+        }
+        let term = inner.current_term;
+        let index = inner.last_index() + 1;
+        inner.log.push(LogEntry {
+            term,
+            payload: EntryPayload::Config(new_config.clone()),
+        });
+        inner.pending_config = true;
+        if let Some(addr) = catch_up_target {
+            // Not trusted toward quorum until it replicates up through the
+            // index this very change lands at.
+            inner.catching_up.insert(addr, index);
+        }
+        inner.apply_config(index, new_config);
+        Ok(())
+    }
+
+    /// The cluster's current membership, as applied from this peer's log so far.
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        self.inner.lock().unwrap().peers.clone()
+    }
+
+    fn step_down(&self, term: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        if term > inner.current_term {
+            inner.current_term = term;
+            inner.voted_for = None;
+        }
+        inner.role = Role::Follower;
+    }
+
+    fn handle_request_vote(&self, args: RequestVoteArgs) -> Result<RequestVoteReply, Error> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.peers.contains(&inner.me) {
+            return Err(Error::NotInCluster);
+        }
+        if args.term > inner.current_term {
+            inner.current_term = args.term;
+            inner.voted_for = None;
+            inner.role = Role::Follower;
+        }
+        if args.term < inner.current_term {
+            return Ok(RequestVoteReply {
+                term: inner.current_term,
+                vote_granted: false,
+            });
+        }
+        let (my_last_index, my_last_term) = inner.last_log_index_term();
+        let up_to_date = args.last_log_term > my_last_term
+            || (args.last_log_term == my_last_term && args.last_log_index >= my_last_index);
+        let can_vote = inner.voted_for.is_none() || inner.voted_for == Some(args.candidate);
+        let vote_granted = can_vote && up_to_date;
+        if vote_granted {
+            inner.voted_for = Some(args.candidate);
+            inner.reset_election_deadline();
+        }
+        Ok(RequestVoteReply {
+            term: inner.current_term,
+            vote_granted,
+        })
+    }
+
+    fn handle_append_entries(&self, args: AppendEntriesArgs) -> Result<AppendEntriesReply, Error> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.peers.contains(&inner.me) {
+            return Err(Error::NotInCluster);
+        }
+        if args.term < inner.current_term {
+            return Ok(AppendEntriesReply {
+                term: inner.current_term,
+                success: false,
+                match_index: 0,
+            });
+        }
+        inner.current_term = args.term;
+        inner.role = Role::Follower;
+        inner.leader = Some(args.leader);
+        inner.reset_election_deadline();
+
+        if args.prev_log_index < inner.snapshot_index {
+            // The leader thinks we need entries we've already compacted away
+            // into a snapshot; nothing here to match against - we'll catch up
+            // via InstallSnapshot instead.
+            return Ok(AppendEntriesReply {
+                term: inner.current_term,
+                success: false,
+                match_index: 0,
+            });
+        }
+        if args.prev_log_index > inner.last_index()
+            || inner.log[inner.arr(args.prev_log_index)].term != args.prev_log_term
+        {
+            return Ok(AppendEntriesReply {
+                term: inner.current_term,
+                success: false,
+                match_index: 0,
+            });
+        }
+
+        let mut index = args.prev_log_index;
+        for entry in args.entries {
+            index += 1;
+            if index <= inner.last_index() {
+                if inner.log[inner.arr(index)].term != entry.term {
+                    let at = inner.arr(index);
+                    inner.log.truncate(at);
+                    inner.log.push(entry.clone());
+                }
+            } else {
+                inner.log.push(entry.clone());
+            }
+            if let EntryPayload::Config(addrs) = &entry.payload {
+                inner.apply_config(index, addrs.clone());
+            }
+        }
+
+        if args.leader_commit > inner.commit_index {
+            inner.commit_index = args.leader_commit.min(index);
+        }
+
+        Ok(AppendEntriesReply {
+            term: inner.current_term,
+            success: true,
+            match_index: index,
+        })
+    }
+
+    fn handle_install_snapshot(
+        &self,
+        args: InstallSnapshotArgs,
+    ) -> Result<InstallSnapshotReply, Error> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.peers.contains(&inner.me) {
+            return Err(Error::NotInCluster);
+        }
+        if args.term < inner.current_term {
+            return Ok(InstallSnapshotReply {
+                term: inner.current_term,
+            });
+        }
+        inner.current_term = args.term;
+        inner.role = Role::Follower;
+        inner.leader = Some(args.leader);
+        inner.reset_election_deadline();
+
+        if args.last_included_index <= inner.snapshot_index {
+            return Ok(InstallSnapshotReply {
+                term: inner.current_term,
+            });
+        }
+
+        if args.last_included_index <= inner.last_index()
+            && inner.log[inner.arr(args.last_included_index)].term == args.last_included_term
+        {
+            // Already have a matching suffix; just trim what the snapshot covers.
+            let keep_from = inner.arr(args.last_included_index);
+            inner.log.drain(0..keep_from);
+        } else {
+            // Log has diverged or doesn't reach that far - the snapshot
+            // replaces it wholesale.
+            inner.log = vec![LogEntry {
+                term: args.last_included_term,
+                payload: EntryPayload::Command(Vec::new()),
+            }];
+        }
+        inner.log[0] = LogEntry {
+            term: args.last_included_term,
+            payload: EntryPayload::Command(Vec::new()),
+        };
+        inner.snapshot_index = args.last_included_index;
+        inner.snapshot_term = args.last_included_term;
+        inner.snapshot_data = args.data.clone();
+        inner.commit_index = inner.commit_index.max(args.last_included_index);
+        inner.last_applied = inner.last_applied.max(args.last_included_index);
+        let _ = inner.apply_tx.unbounded_send(ApplyMsg::Snapshot {
+            index: args.last_included_index,
+            term: args.last_included_term,
+            data: args.data,
+        });
+
+        Ok(InstallSnapshotReply {
+            term: inner.current_term,
+        })
+    }
+
+    fn spawn_election_timer(&self) {
+        let this = self.clone();
+        let handle = task::spawn_local(async move {
+            loop {
+                time::sleep(Duration::from_millis(10)).await;
+                let should_elect = {
+                    let inner = this.inner.lock().unwrap();
+                    // A node no longer in its own view of the membership has
+                    // been removed from the cluster; it must stop calling
+                    // elections so it can't keep disrupting a cluster it's no
+                    // longer part of.
+                    inner.peers.contains(&inner.me)
+                        && inner.role != Role::Leader
+                        && time::Instant::now() >= inner.election_deadline
+                };
+                if should_elect {
+                    this.start_election().await;
+                }
+            }
+        });
+        self._tasks.lock().unwrap().push(handle);
+    }
+
+    async fn start_election(&self) {
+        let (term, me, last_log_index, last_log_term, peers) = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.role = Role::Candidate;
+            inner.current_term += 1;
+            inner.voted_for = Some(inner.me);
+            inner.reset_election_deadline();
+            let (last_log_index, last_log_term) = inner.last_log_index_term();
+            (
+                inner.current_term,
+                inner.me,
+                last_log_index,
+                last_log_term,
+                inner.peers.clone(),
+            )
+        };
+
+        let net = net::NetLocalHandle::current();
+        let args = RequestVoteArgs {
+            term,
+            candidate: me,
+            last_log_index,
+            last_log_term,
+        };
+        let replies = futures::future::join_all(peers.iter().filter(|&&p| p != me).map(|&peer| {
+            let net = net.clone();
+            let args = args.clone();
+            async move { net.call::<_, Result<RequestVoteReply, Error>>(peer, args).await }
+        }))
+        .await;
+
+        let mut votes = 1; // self
+        for reply in replies.into_iter().flatten() {
+            let Ok(reply) = reply else { continue };
+            if reply.term > term {
+                self.step_down(reply.term);
+                return;
+            }
+            if reply.vote_granted {
+                votes += 1;
+            }
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.role == Role::Candidate && inner.current_term == term && votes * 2 > peers.len() {
+            inner.role = Role::Leader;
+            inner.leader = Some(me);
+            let next = inner.last_index() + 1;
+            inner.next_index = peers.iter().map(|&p| (p, next)).collect();
+            inner.match_index = peers.iter().map(|&p| (p, 0)).collect();
+            // A change proposed by a since-superseded leader may never
+            // commit now; don't leave this node permanently unable to
+            // propose another one just because it used to be stuck waiting
+            // on that one.
+            inner.pending_config = false;
+            // A no-op in the new term, so `read_index` has something of this
+            // leader's own to wait on committing - bincode encodes an empty
+            // `Vec<T>` as just its length prefix, so this decodes fine as
+            // the empty command batch `kvraft::Server` expects.
+            inner.log.push(LogEntry {
+                term,
+                payload: EntryPayload::Command(bincode::serialize(&Vec::<()>::new()).unwrap()),
+            });
+        }
+    }
+
+    fn spawn_heartbeat_loop(&self) {
+        let this = self.clone();
+        let handle = task::spawn_local(async move {
+            loop {
+                time::sleep(HEARTBEAT_INTERVAL).await;
+                // A removed leader (still leader in its own stale view) must
+                // stop asserting leadership over a cluster it's no longer a
+                // member of.
+                if this.is_leader() && this.is_member() {
+                    this.broadcast_append_entries().await;
+                }
+            }
+        });
+        self._tasks.lock().unwrap().push(handle);
+    }
+
+    async fn broadcast_append_entries(&self) {
+        let (me, term, peers, commit_index, snapshot_index, snapshot_term, snapshot_data) = {
+            let inner = self.inner.lock().unwrap();
+            (
+                inner.me,
+                inner.current_term,
+                inner.peers.clone(),
+                inner.commit_index,
+                inner.snapshot_index,
+                inner.snapshot_term,
+                inner.snapshot_data.clone(),
+            )
+        };
+        let net = net::NetLocalHandle::current();
+
+        // Peers whose `next_index` falls at or before the last snapshot can't
+        // be helped by AppendEntries (the entries they'd need are gone); send
+        // them the snapshot instead, piggybacking on this heartbeat tick.
+        let lagging: Vec<SocketAddr> = peers
+            .iter()
+            .filter(|&&p| p != me)
+            .copied()
+            .filter(|&p| {
+                snapshot_index > 0 && {
+                    let inner = self.inner.lock().unwrap();
+                    let next = *inner.next_index.get(&p).unwrap_or(&(inner.last_index() + 1));
+                    next <= snapshot_index
+                }
+            })
+            .collect();
+
+        if !lagging.is_empty() {
+            let results = futures::future::join_all(lagging.iter().map(|&peer| {
+                let net = net.clone();
+                let args = InstallSnapshotArgs {
+                    term,
+                    leader: me,
+                    last_included_index: snapshot_index,
+                    last_included_term: snapshot_term,
+                    data: snapshot_data.clone(),
+                };
+                async move {
+                    (
+                        peer,
+                        net.call::<_, Result<InstallSnapshotReply, Error>>(peer, args).await,
+                    )
+                }
+            }))
+            .await;
+            let mut inner = self.inner.lock().unwrap();
+            for (peer, reply) in results {
+                let Ok(Ok(reply)) = reply else { continue };
+                if reply.term > inner.current_term {
+                    drop(inner);
+                    self.step_down(reply.term);
+                    return;
+                }
+                inner.next_index.insert(peer, snapshot_index + 1);
+                inner.match_index.insert(peer, snapshot_index);
+                if let Some(&target) = inner.catching_up.get(&peer) {
+                    if snapshot_index >= target {
+                        inner.catching_up.remove(&peer);
+                    }
+                }
+            }
+        }
+
+        let replies = futures::future::join_all(
+            peers
+                .iter()
+                .filter(|&&p| p != me && !lagging.contains(&p))
+                .map(|&peer| {
+                    let net = net.clone();
+                    let (prev_log_index, prev_log_term, entries) = {
+                        let inner = self.inner.lock().unwrap();
+                        let next = *inner.next_index.get(&peer).unwrap_or(&(inner.last_index() + 1));
+                        let prev_log_index = next.saturating_sub(1);
+                        let prev_log_term = inner.log[inner.arr(prev_log_index)].term;
+                        let entries = inner.log[inner.arr(prev_log_index) + 1..].to_vec();
+                        (prev_log_index, prev_log_term, entries)
+                    };
+                    let args = AppendEntriesArgs {
+                        term,
+                        leader: me,
+                        prev_log_index,
+                        prev_log_term,
+                        entries,
+                        leader_commit: commit_index,
+                    };
+                    async move {
+                        (
+                            peer,
+                            net.call::<_, Result<AppendEntriesReply, Error>>(peer, args).await,
+                        )
+                    }
+                }),
+        )
+        .await;
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.role != Role::Leader || inner.current_term != term {
+            return;
+        }
+        for (peer, reply) in replies {
+            let Ok(Ok(reply)) = reply else { continue };
+            if reply.term > inner.current_term {
+                drop(inner);
+                self.step_down(reply.term);
+                return;
+            }
+            if reply.success {
+                inner.match_index.insert(peer, reply.match_index);
+                inner.next_index.insert(peer, reply.match_index + 1);
+                if let Some(&target) = inner.catching_up.get(&peer) {
+                    if reply.match_index >= target {
+                        inner.catching_up.remove(&peer);
+                    }
+                }
+            } else {
+                // May fall to `snapshot_index` itself, which routes the peer
+                // to InstallSnapshot on the next tick.
+                let floor = inner.snapshot_index.max(1);
+                let next = inner.next_index.entry(peer).or_insert(1);
+                *next = next.saturating_sub(1).max(floor);
+            }
+        }
+        inner.advance_commit_index();
+    }
+
+    fn spawn_apply_loop(&self) {
+        let this = self.clone();
+        let handle = task::spawn_local(async move {
+            loop {
+                time::sleep(Duration::from_millis(5)).await;
+                this.apply_committed();
+            }
+        });
+        self._tasks.lock().unwrap().push(handle);
+    }
+
+    fn apply_committed(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        while inner.last_applied < inner.commit_index {
+            let index = inner.last_applied + 1;
+            let entry = inner.log[inner.arr(index)].clone();
+            inner.last_applied = index;
+            match entry.payload {
+                EntryPayload::Command(data) => {
+                    let _ = inner.apply_tx.unbounded_send(ApplyMsg::Command { index, data });
+                }
+                EntryPayload::Config(addrs) => {
+                    // Membership is already live (applied at append time); a
+                    // config entry only needs `commit_peers` advanced here so
+                    // the *next* membership change requires a majority that
+                    // includes this one.
+                    inner.commit_peers = addrs;
+                    inner.pending_config = false;
+                }
+            }
+        }
+    }
+}
+
+impl Inner {
+    /// Converts a raft log index into its position in `log`.
+    fn arr(&self, index: u64) -> usize {
+        (index - self.snapshot_index) as usize
+    }
+
+    /// The raft index of the last entry in `log`.
+    fn last_index(&self) -> u64 {
+        self.snapshot_index + self.log.len() as u64 - 1
+    }
+
+    fn last_log_index_term(&self) -> (u64, u64) {
+        let index = self.last_index();
+        (index, self.log[self.arr(index)].term)
+    }
+
+    fn reset_election_deadline(&mut self) {
+        self.election_deadline = time::Instant::now() + election_timeout(self.me, self.current_term);
+    }
+
+    /// Best-effort guess at who the leader might be, for `Error::NotLeader`'s
+    /// hint - the index of `leader` in `peers`, or `0` if unknown.
+    fn leader_hint(&self) -> usize {
+        self.leader
+            .and_then(|l| self.peers.iter().position(|&p| p == l))
+            .unwrap_or(0)
+    }
+
+    /// Applies a `Config` entry's membership as soon as it is appended to this
+    /// peer's log (leader or follower) - *not* once committed - so the cluster
+    /// is already routing to the new configuration while the change itself is
+    /// still replicating. `commit_peers` (used for majority counting) only
+    /// catches up once the entry commits, in `apply_committed`. Also keeps
+    /// `next_index`/`match_index` in sync with the new peer set, since a
+    /// server added mid-term otherwise has no entry in either map.
+    fn apply_config(&mut self, index: u64, addrs: Vec<SocketAddr>) {
+        for &addr in &addrs {
+            if addr != self.me {
+                self.next_index.entry(addr).or_insert(self.snapshot_index + 1);
+                self.match_index.entry(addr).or_insert(0);
+            }
+        }
+        self.next_index.retain(|addr, _| addrs.contains(addr));
+        self.match_index.retain(|addr, _| addrs.contains(addr));
+        self.peers = addrs.clone();
+        let _ = self.apply_tx.unbounded_send(ApplyMsg::Config { index, addrs });
+    }
+
+    fn advance_commit_index(&mut self) {
+        for index in (self.commit_index + 1..=self.last_index()).rev() {
+            if self.log[self.arr(index)].term != self.current_term {
+                continue;
+            }
+            let mut acks = 1; // self
+            for &peer in &self.commit_peers {
+                if peer == self.me {
+                    continue;
+                }
+                let match_index = self.match_index.get(&peer).copied().unwrap_or(0);
+                let caught_up = match self.catching_up.get(&peer) {
+                    Some(&target) => match_index >= target,
+                    None => true,
+                };
+                if caught_up && match_index >= index {
+                    acks += 1;
+                }
+            }
+            if acks * 2 > self.commit_peers.len() {
+                self.commit_index = index;
+                break;
+            }
+        }
+    }
+}
+
+fn election_timeout(me: SocketAddr, term: u64) -> Duration {
+    let mut hasher = DefaultHasher::new();
+    me.hash(&mut hasher);
+    term.hash(&mut hasher);
+    let span = (ELECTION_TIMEOUT_MAX - ELECTION_TIMEOUT_MIN).as_millis() as u64;
+    ELECTION_TIMEOUT_MIN + Duration::from_millis(hasher.finish() % span.max(1))
+}
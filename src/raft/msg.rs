@@ -0,0 +1,120 @@
+use madsim::Request;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+
+#[derive(thiserror::Error, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Error {
+    /// Not the leader. `0` is a hint at which peer (index into the current
+    /// membership) might be, so the caller can retry there first.
+    #[error("not leader, hint: {0}")]
+    NotLeader(usize),
+    #[error("no longer in the cluster")]
+    NotInCluster,
+    /// A membership change is already replicating; the caller must wait for
+    /// it to commit before proposing another.
+    #[error("a membership change is already pending")]
+    ConfigChangePending,
+    /// The leader hasn't yet committed a no-op entry in its current term, so
+    /// it can't safely answer a `read_index` request - its view of
+    /// `commit_index` might still be stale from a prior term.
+    #[error("leader not yet ready to serve reads this term")]
+    NotReady,
+}
+
+/// What the leader appended and replicated this entry for.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum EntryPayload {
+    /// An opaque, `bincode`-encoded command from the layer above (e.g. kvraft).
+    Command(Vec<u8>),
+    /// A single-server membership change: the full new set of peer addresses.
+    Config(Vec<SocketAddr>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct LogEntry {
+    pub term: u64,
+    pub payload: EntryPayload,
+}
+
+/// Returned by [`super::RaftHandle::start`]: where the command landed in the log.
+#[derive(Debug, Clone, Copy)]
+pub struct Start {
+    pub index: u64,
+    pub term: u64,
+}
+
+/// What the layer above (e.g. kvraft's `Server`) receives as log entries become
+/// safe to act on. `Command` and `Snapshot` are only ever delivered once
+/// committed; `Config` is delivered as soon as the entry is appended to this
+/// peer's log (leader or follower), before it is known to be committed, so that
+/// old and new majorities always overlap during a membership change.
+#[derive(Debug, Clone)]
+pub enum ApplyMsg {
+    Command {
+        index: u64,
+        data: Vec<u8>,
+    },
+    Snapshot {
+        index: u64,
+        term: u64,
+        data: Vec<u8>,
+    },
+    Config {
+        index: u64,
+        addrs: Vec<SocketAddr>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Request)]
+#[rtype("Result<RequestVoteReply, Error>")]
+pub(crate) struct RequestVoteArgs {
+    pub term: u64,
+    pub candidate: SocketAddr,
+    pub last_log_index: u64,
+    pub last_log_term: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct RequestVoteReply {
+    pub term: u64,
+    pub vote_granted: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Request)]
+#[rtype("Result<AppendEntriesReply, Error>")]
+pub(crate) struct AppendEntriesArgs {
+    pub term: u64,
+    pub leader: SocketAddr,
+    pub prev_log_index: u64,
+    pub prev_log_term: u64,
+    pub entries: Vec<LogEntry>,
+    pub leader_commit: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct AppendEntriesReply {
+    pub term: u64,
+    pub success: bool,
+    /// Index this follower's log now matches the leader's up to, used to fast
+    /// forward `next_index` instead of decrementing one at a time.
+    pub match_index: u64,
+}
+
+/// Brings a follower whose `next_index` has fallen behind what the leader
+/// still has in-memory (because it was compacted into a snapshot) up to
+/// date in one shot, instead of leaving it to retry AppendEntries forever
+/// against a `prev_log_index` the leader can no longer match.
+#[derive(Debug, Clone, Serialize, Deserialize, Request)]
+#[rtype("Result<InstallSnapshotReply, Error>")]
+pub(crate) struct InstallSnapshotArgs {
+    pub term: u64,
+    pub leader: SocketAddr,
+    pub last_included_index: u64,
+    pub last_included_term: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct InstallSnapshotReply {
+    pub term: u64,
+}